@@ -1,13 +1,15 @@
 require("@nomiclabs/hardhat-waffle");
 
 const neon_token_deployer = process.env.DEPLOYER_PRIVATE_KEY;
+// Other signers are selected in scripts/deploy.js via SIGNER
+const deployer_accounts = neon_token_deployer ? [neon_token_deployer] : [];
 
 module.exports = {
   solidity: "0.8.4",
   networks: {
     ci: {
       url: 'http://proxy:9090/solana',
-      accounts: [neon_token_deployer],
+      accounts: deployer_accounts,
       network_id: 111,
       chainId: 111,
       gas: 3000000,
@@ -19,7 +21,7 @@ module.exports = {
     },
     devnet: {
       url: 'https://proxy.devnet.neonlabs.org/solana',
-      accounts: [neon_token_deployer],
+      accounts: deployer_accounts,
       network_id: 245022926,
       chainId: 245022926,
       gas: 3000000,
@@ -31,7 +33,7 @@ module.exports = {
     },
     testnet: {
       url: 'https://proxy.testnet.neonlabs.org/solana',
-      accounts: [neon_token_deployer],
+      accounts: deployer_accounts,
       network_id: 245022940,
       chainId: 245022940,
       gas: 3000000,
@@ -2,9 +2,135 @@ const hre = require("hardhat");
 const fs = require("fs");
 const { base58_to_binary } = require('base58-js')
 const { execSync } = require("child_process");
+const readline = require("readline");
 
 const solana_url = process.env.SOLANA_URL;
 const spl_token_authority = process.env.SPL_TOKEN_AUTHORITY;
+const dry_run = !!process.env.DRY_RUN;
+const report_file = process.env.REPORT_FILE;
+const checkpoint_file = process.env.CHECKPOINT_FILE;
+const allow_nonce_gap = !!process.env.ALLOW_NONCE_GAP;
+const skip_tokens = (process.env.SKIP_TOKENS || '').split(',').filter(s => s);
+const only_tokens = (process.env.ONLY_TOKENS || '').split(',').filter(s => s);
+const signer_backend = process.env.SIGNER || 'key';
+// LedgerSigner only signs legacy fields: it keeps gasPrice and drops the
+// type and EIP-1559 fees, so Ledger deployments are always legacy (type 0)
+// with an explicit gas price
+const legacy_tx = !!process.env.LEGACY_TX || signer_backend == 'ledger';
+
+// Account signing all deployment transactions, selected by SIGNER
+let deployer;
+let deployer_address;
+
+// Gas limit and fee fields added to every deployment transaction
+let overrides = {};
+
+// Chain id and deployer nonce at the start of the first run, and contracts
+// deployed so far keyed by contract name or token symbol
+let checkpoint = { contracts: {} };
+
+// Deployed contracts, written to report_file when it is set
+let report = [];
+
+// Deployer nonce used to predict contract addresses in dry-run mode
+let next_nonce = 0;
+
+// Signs with the Clef external API (account_signTransaction, account_signData)
+// and broadcasts the signed transactions through the network provider
+class ExternalSigner extends hre.ethers.Signer {
+  constructor(url, address, provider) {
+    super();
+    this.url = url;
+    this.address = hre.ethers.utils.getAddress(address);
+    this.external = new hre.ethers.providers.JsonRpcProvider(url);
+    hre.ethers.utils.defineReadOnly(this, "provider", provider);
+  }
+
+  async getAddress() {
+    return this.address;
+  }
+
+  async signMessage(message) {
+    const data = (typeof message === "string") ? hre.ethers.utils.toUtf8Bytes(message) : message;
+    return await this.external.send("account_signData", ["text/plain", this.address, hre.ethers.utils.hexlify(data)]);
+  }
+
+  async signTransaction(transaction) {
+    const tx = await hre.ethers.utils.resolveProperties(transaction);
+    const request = hre.ethers.providers.JsonRpcProvider.hexlifyTransaction(tx, { from: true });
+    // Clef returns the signed transaction both RLP-encoded and decoded
+    const result = await this.external.send("account_signTransaction", [request]);
+    return result.raw;
+  }
+
+  connect(provider) {
+    return new ExternalSigner(this.url, this.address, provider);
+  }
+}
+
+function promptPassword(question) {
+  return new Promise(resolve => {
+    const rl = readline.createInterface({ input: process.stdin, output: process.stdout, terminal: true });
+    process.stdout.write(question);
+    rl._writeToOutput = () => {};
+    rl.question('', answer => {
+      rl.close();
+      process.stdout.write('\n');
+      resolve(answer);
+    });
+  });
+}
+
+async function getDeployer() {
+  switch (signer_backend) {
+    case 'key': {
+      // Without a configured key, getSigners() returns the node's eth_accounts
+      if (!process.env.DEPLOYER_PRIVATE_KEY) {
+        throw new Error("DEPLOYER_PRIVATE_KEY is not set");
+      }
+      const [signer] = await hre.ethers.getSigners();
+      return signer;
+    }
+    case 'keystore': {
+      const keystore_file = process.env.KEYSTORE_FILE;
+      if (!keystore_file) {
+        throw new Error("KEYSTORE_FILE is required for the keystore signer");
+      }
+      const password = process.env.KEYSTORE_PASSWORD || await promptPassword(`Password for ${keystore_file}: `);
+      const wallet = await hre.ethers.Wallet.fromEncryptedJson(fs.readFileSync(keystore_file, 'utf8'), password);
+      return wallet.connect(hre.ethers.provider);
+    }
+    case 'ledger': {
+      // Not a dependency of this package, install it to use a Ledger device
+      const { LedgerSigner } = require("@ethersproject/hardware-wallets");
+      return new LedgerSigner(hre.ethers.provider, "hid", process.env.LEDGER_PATH);
+    }
+    case 'rpc': {
+      if (!process.env.SIGNER_URL || !process.env.SIGNER_ADDRESS) {
+        throw new Error("SIGNER_URL and SIGNER_ADDRESS are required for the rpc signer");
+      }
+      return new ExternalSigner(process.env.SIGNER_URL, process.env.SIGNER_ADDRESS, hre.ethers.provider);
+    }
+    default:
+      throw new Error(`Unknown SIGNER ${signer_backend}, expected one of: key, keystore, ledger, rpc`);
+  }
+}
+
+async function planDeployment(name, factory, ...args) {
+  const address = hre.ethers.utils.getContractAddress({ from: deployer_address, nonce: next_nonce });
+  next_nonce += 1;
+
+  const code = await hre.ethers.provider.getCode(address);
+  if (code != '0x') {
+    console.log(`[dry-run] ERROR ${name}: code already exists at ${address}, the deployment would collide with it and fail`);
+    process.exitCode = 1;
+    return address;
+  }
+
+  const gas = await deployer.estimateGas(factory.getDeployTransaction(...args, overrides));
+  console.log(`[dry-run] ${name}: would deploy to ${address}, estimated gas ${gas.toString()}`);
+  return address;
+}
 
 function createSplToken(spl_token) {
   console.log(`\n\n\nCreating SPL token ${spl_token.symbol}...`);
@@ -19,7 +145,12 @@ function createSplToken(spl_token) {
     spl_token.address_spl = String(execSync(`solana address -k "${token_keyfile}"`)).trim();
     console.log(`SPL token address is ${spl_token.address_spl}`)
 
-    stdout = execSync(`spl-token --url ${solana_url} create-token --owner ${spl_token_authority} -- "${token_keyfile}"`);
+    if (dry_run) {
+      console.log(`[dry-run] SPL token ${spl_token.symbol}: would create ${spl_token.address_spl}`);
+      return true;
+    }
+
+    stdout = execSync(`spl-token --url ${solana_url} create-token --owner ${spl_token_authority} --decimals ${spl_token.decimals} -- "${token_keyfile}"`);
     console.log(`SPL token ${spl_token.symbol} created: ${spl_token.address_spl}`);
     return true;
   } catch (e) {
@@ -28,75 +159,367 @@ function createSplToken(spl_token) {
   }
 }
 
+function gwei(name) {
+  const value = process.env[name];
+  return value ? hre.ethers.utils.parseUnits(value, 'gwei') : undefined;
+}
+
+async function prepareOverrides() {
+  const eip1559_fees = process.env.MAX_FEE_PER_GAS || process.env.MAX_PRIORITY_FEE_PER_GAS;
+  if (signer_backend == 'ledger' && eip1559_fees) {
+    throw new Error("Ledger signer sends legacy transactions only, use GAS_PRICE instead of MAX_FEE_PER_GAS/MAX_PRIORITY_FEE_PER_GAS");
+  }
+  if (process.env.GAS_PRICE && eip1559_fees) {
+    throw new Error("GAS_PRICE selects legacy transactions and cannot be combined with MAX_FEE_PER_GAS/MAX_PRIORITY_FEE_PER_GAS");
+  }
+
+  if (process.env.GAS_LIMIT) {
+    overrides.gasLimit = hre.ethers.BigNumber.from(process.env.GAS_LIMIT);
+  }
+
+  const fee_data = await hre.ethers.provider.getFeeData();
+  // An explicit GAS_PRICE is a request for legacy transactions
+  if (legacy_tx || process.env.GAS_PRICE || !fee_data.maxFeePerGas) {
+    // Without an explicit type, locally signed transactions are upgraded to
+    // EIP-1559 when the network reports EIP-1559 fee data
+    overrides.type = 0;
+
+    const config_gas_price = hre.network.config.gasPrice;
+    overrides.gasPrice = gwei('GAS_PRICE')
+        || ((typeof config_gas_price === 'number') ? hre.ethers.BigNumber.from(config_gas_price) : fee_data.gasPrice);
+    if (!overrides.gasPrice) {
+      throw new Error("Network reports no gas price, set GAS_PRICE");
+    }
+    console.log(`Using legacy transactions, gas price ${hre.ethers.utils.formatUnits(overrides.gasPrice, 'gwei')} gwei`);
+    return;
+  }
+
+  overrides.maxFeePerGas = gwei('MAX_FEE_PER_GAS') || fee_data.maxFeePerGas;
+  overrides.maxPriorityFeePerGas = gwei('MAX_PRIORITY_FEE_PER_GAS') || fee_data.maxPriorityFeePerGas;
+  if (overrides.maxPriorityFeePerGas.gt(overrides.maxFeePerGas)) {
+    throw new Error(`Priority fee ${hre.ethers.utils.formatUnits(overrides.maxPriorityFeePerGas, 'gwei')} gwei exceeds max fee ${hre.ethers.utils.formatUnits(overrides.maxFeePerGas, 'gwei')} gwei`);
+  }
+  console.log(`Using EIP-1559 transactions, max fee ${hre.ethers.utils.formatUnits(overrides.maxFeePerGas, 'gwei')} gwei, priority fee ${hre.ethers.utils.formatUnits(overrides.maxPriorityFeePerGas, 'gwei')} gwei`);
+}
+
+function writeCheckpoint() {
+  if (checkpoint_file && !dry_run) {
+    fs.writeFileSync(checkpoint_file, JSON.stringify(checkpoint, null, ' '));
+  }
+}
+
+async function loadCheckpoint() {
+  if (!checkpoint_file) {
+    return;
+  }
+
+  const { chainId } = await hre.ethers.provider.getNetwork();
+  if (!fs.existsSync(checkpoint_file)) {
+    checkpoint = { chainId, start_nonce: await deployer.getTransactionCount(), contracts: {} };
+    // Written before the first deployment, so that a run interrupted right after sending it is detected
+    writeCheckpoint();
+    return;
+  }
+
+  checkpoint = JSON.parse(fs.readFileSync(checkpoint_file, 'utf8'));
+  if (checkpoint.chainId != chainId) {
+    throw new Error(`${checkpoint_file} was written for chain ${checkpoint.chainId}, but the network chain is ${chainId}`);
+  }
+  console.log(`Resuming from ${checkpoint_file}: ${Object.keys(checkpoint.contracts).join(', ') || 'nothing'} already deployed`);
+}
+
+function saveCheckpoint(key, contract) {
+  checkpoint.contracts[key] = {
+    address: contract.address,
+    nonce: contract.deployTransaction.nonce,
+    tx_hash: contract.deployTransaction.hash,
+  };
+  writeCheckpoint();
+}
+
+function nonceGap(message) {
+  if (!allow_nonce_gap) {
+    throw new Error(`${message}. Set ALLOW_NONCE_GAP=1 to continue anyway`);
+  }
+  console.log(`WARNING: ${message}`);
+}
+
+async function checkNonceGap() {
+  if (!checkpoint_file) {
+    return;
+  }
+
+  const nonces = Object.values(checkpoint.contracts).map(entry => entry.nonce);
+  const expected = (nonces.length > 0) ? Math.max(...nonces) + 1 : checkpoint.start_nonce;
+  const actual = await deployer.getTransactionCount();
+  if (actual == expected) {
+    return;
+  }
+
+  nonceGap(`Deployer nonce is ${actual}, but ${checkpoint_file} expects ${expected}: addresses of the remaining contracts will differ from other networks`);
+}
+
+async function alreadyDeployed(key) {
+  const entry = checkpoint.contracts[key];
+  if (!entry) {
+    return null;
+  }
+
+  const code = await hre.ethers.provider.getCode(entry.address);
+  if (code == '0x') {
+    nonceGap(`${key} is in ${checkpoint_file} at ${entry.address}, but has no code there: deploying it again will put it at a different address than on other networks`);
+    delete checkpoint.contracts[key];
+    writeCheckpoint();
+    return null;
+  }
+
+  console.log(`${key} already deployed at ${entry.address} (nonce ${entry.nonce}), skipping`);
+  return entry;
+}
+
+function isTokenSelected(spl_token) {
+  if (only_tokens.length > 0 && !only_tokens.includes(spl_token.symbol)) {
+    return false;
+  }
+  return !skip_tokens.includes(spl_token.symbol);
+}
+
+// Tokens deployed after a filtered-out token get a different nonce, and so
+// a different address, than on networks where the full list was deployed
+function checkTokenFilters(token_list_file) {
+  const chainId = hre.network.config.chainId;
+  const token_list = JSON.parse(fs.readFileSync(token_list_file, 'utf8'));
+
+  let filtered = false;
+  let shifted = [];
+  for (const spl_token of token_list.tokens) {
+    if (chainId != spl_token.chainId) {
+      continue;
+    }
+
+    // Tokens from the checkpoint do not consume a nonce in this run
+    const deployed = !!checkpoint.contracts[spl_token.symbol];
+    if (!isTokenSelected(spl_token)) {
+      filtered = filtered || !deployed;
+    } else if (filtered && !deployed) {
+      shifted.push(spl_token.symbol);
+    }
+  }
+
+  if (shifted.length == 0) {
+    return;
+  }
+
+  nonceGap(`SKIP_TOKENS/ONLY_TOKENS shift the deployer nonce of ${shifted.join(', ')}: their addresses will differ from other networks`);
+}
+
 async function deployNeon() {
-  const Neon = await hre.ethers.getContractFactory("NeonToken");
-  const neon = await Neon.deploy();
+  const Neon = await hre.ethers.getContractFactory("NeonToken", deployer);
+  const deployed = await alreadyDeployed("NeonToken");
+  if (deployed) {
+    await addResumedToReport({ name: "NeonToken" }, deployed);
+    return;
+  }
+  if (dry_run) {
+    await planDeployment("Neon contract", Neon);
+    return;
+  }
+
+  const neon = await Neon.deploy(overrides);
 
   await neon.deployed();
   console.log("Neon contract address is: ", neon.address);
+  saveCheckpoint("NeonToken", neon);
+  await addToReport({ name: "NeonToken" }, neon);
 }
 
 async function deployQueryAccount() {
-  const QueryAccount = await hre.ethers.getContractFactory("QueryAccount");
-  const queryAccount = await QueryAccount.deploy();
+  const QueryAccount = await hre.ethers.getContractFactory("QueryAccount", deployer);
+  const deployed = await alreadyDeployed("QueryAccount");
+  if (deployed) {
+    await addResumedToReport({ name: "QueryAccount" }, deployed);
+    return;
+  }
+  if (dry_run) {
+    await planDeployment("QueryAccount library", QueryAccount);
+    return;
+  }
+
+  const queryAccount = await QueryAccount.deploy(overrides);
 
   await queryAccount.deployed();
   console.log("QueryAccount library address is: ", queryAccount.address);
+  saveCheckpoint("QueryAccount", queryAccount);
+  await addToReport({ name: "QueryAccount" }, queryAccount);
+}
+
+async function addToReport(entry, contract) {
+  const receipt = await contract.deployTransaction.wait();
+  entry.address = contract.address;
+  entry.tx_hash = contract.deployTransaction.hash;
+  entry.gas_used = receipt.gasUsed.toString();
+  report.push(entry);
+  return entry;
+}
+
+// Contracts deployed by a previous run are reported from the checkpoint
+async function addResumedToReport(entry, deployed) {
+  const receipt = await hre.ethers.provider.getTransactionReceipt(deployed.tx_hash);
+  entry.address = deployed.address;
+  entry.tx_hash = deployed.tx_hash;
+  entry.gas_used = receipt ? receipt.gasUsed.toString() : null;
+  entry.resumed = true;
+  report.push(entry);
+  return entry;
+}
+
+async function checkWrapper(entry, spl_token) {
+  entry.verification_errors = await verifyWrapper(spl_token, entry.address);
+  if (entry.verification_errors.length > 0) {
+    console.log(`   Wrapper verification failed: ${entry.verification_errors.join('; ')}`);
+    process.exitCode = 1;
+  } else {
+    console.log(`   Wrapper verified`);
+  }
+}
+
+async function verifyWrapper(spl_token, address) {
+  const wrapper = await hre.ethers.getContractAt("NeonERC20Wrapper", address);
+  const erc20 = await hre.ethers.getContractAt("IERC20", address);
+
+  const expected = {
+    name: spl_token.name,
+    symbol: spl_token.symbol,
+    decimals: spl_token.decimals,
+    tokenMint: hre.ethers.utils.hexlify(base58_to_binary(spl_token.address_spl)),
+  };
+  const getters = {
+    name: () => wrapper.name(),
+    symbol: () => wrapper.symbol(),
+    decimals: () => erc20.decimals(),
+    tokenMint: () => wrapper.tokenMint(),
+  };
+
+  let errors = [];
+  for (const field in expected) {
+    let actual;
+    try {
+      actual = await getters[field]();
+    } catch (e) {
+      errors.push(`${field}: call failed: ${e.reason || e.message}`);
+      continue;
+    }
+
+    // Only the mint is a hex string, whose case does not matter
+    const matches = (field == 'tokenMint')
+        ? String(expected[field]).toLowerCase() == String(actual).toLowerCase()
+        : String(expected[field]) == String(actual);
+    if (!matches) {
+      errors.push(`${field}: expected ${expected[field]}, got ${actual}`);
+    }
+  }
+  return errors;
+}
+
+function writeReport(error) {
+  if (!report_file || dry_run) {
+    return;
+  }
+
+  let content = { chainId: hre.network.config.chainId, contracts: report };
+  if (error) {
+    content.error = String(error);
+  }
+  fs.writeFileSync(report_file, JSON.stringify(content, null, ' '));
+  console.log(`Deployment report written to ${report_file}`);
 }
 
 async function deployERC20(token_list_file) {
-  fs.readFile(token_list_file, 'utf8' , async (err, data) => {
-    if (err) {
-      console.error(err)
-      return
+  const data = fs.readFileSync(token_list_file, 'utf8');
+  const chainId = hre.network.config.chainId;
+  let token_list = JSON.parse(data);
+  const NeonERC20Wrapper = await hre.ethers.getContractFactory("NeonERC20Wrapper", deployer);
+
+  for (let spl_token of token_list.tokens) {
+    if (chainId != spl_token.chainId) {
+      continue;
     }
 
-    const chainId = hre.network.config.chainId;
-    let token_list = JSON.parse(data);
-    const NeonERC20Wrapper = await hre.ethers.getContractFactory("NeonERC20Wrapper");
+    if (!isTokenSelected(spl_token)) {
+      console.log(`Skipping SPL token ${spl_token.symbol}`);
+      continue;
+    }
 
-    for (let spl_token of token_list.tokens) {
-      if (chainId != spl_token.chainId) {
-        continue;
-      }
+    const deployed = await alreadyDeployed(spl_token.symbol);
+    if (deployed) {
+      spl_token.address = deployed.address;
+      const entry = await addResumedToReport({ name: spl_token.name, symbol: spl_token.symbol, address_spl: spl_token.address_spl }, deployed);
+      await checkWrapper(entry, spl_token);
+      continue;
+    }
 
-      if (!createSplToken(spl_token)) {
-        continue;
-      }
+    if (!createSplToken(spl_token)) {
+      continue;
+    }
 
-      console.log(`Deploying wrapper for SPL token ${spl_token.name} (${spl_token.symbol})`);
-      const new_wrapper = await NeonERC20Wrapper.deploy(
+    if (dry_run) {
+      await planDeployment(
+          `Wrapper for SPL token ${spl_token.name} (${spl_token.symbol})`,
+          NeonERC20Wrapper,
           spl_token.name,
           spl_token.symbol,
           base58_to_binary(spl_token.address_spl));
-
-      await new_wrapper.deployed();
-      console.log(`   Wrapper deployed: ${new_wrapper.address}`);
-      spl_token.address = new_wrapper.address;
+      continue;
     }
 
-    fs.writeFile(
-        token_list_file,
-        JSON.stringify(token_list, null, ' '),
-        function(err) {
-          if (err) {
-            console.log(err);
-          }
-        });
-  });
+    console.log(`Deploying wrapper for SPL token ${spl_token.name} (${spl_token.symbol})`);
+    const new_wrapper = await NeonERC20Wrapper.deploy(
+        spl_token.name,
+        spl_token.symbol,
+        base58_to_binary(spl_token.address_spl),
+        overrides);
+
+    await new_wrapper.deployed();
+    console.log(`   Wrapper deployed: ${new_wrapper.address}`);
+    spl_token.address = new_wrapper.address;
+    saveCheckpoint(spl_token.symbol, new_wrapper);
+
+    const entry = await addToReport({ name: spl_token.name, symbol: spl_token.symbol, address_spl: spl_token.address_spl }, new_wrapper);
+    await checkWrapper(entry, spl_token);
+  }
+
+  if (dry_run) {
+    console.log(`[dry-run] ${token_list_file} is left unchanged`);
+    return;
+  }
+
+  fs.writeFileSync(token_list_file, JSON.stringify(token_list, null, ' '));
 }
 
 async function main() {
-  const [deployer] = await ethers.getSigners();
-  console.log("Deploying contracts with the account:", deployer.address);
+  deployer = await getDeployer();
+  deployer_address = await deployer.getAddress();
+  console.log("Deploying contracts with the account:", deployer_address);
+
+  await loadCheckpoint();
+  await checkNonceGap();
+  checkTokenFilters('./tokenlist.json');
+  await prepareOverrides();
+
+  if (dry_run) {
+    next_nonce = await deployer.getTransactionCount();
+    console.log(`[dry-run] No transactions will be sent, deployer nonce is ${next_nonce}`);
+  }
 
   await deployNeon();
   await deployQueryAccount();
   await deployERC20('./tokenlist.json');
+  writeReport();
 }
 
 main().catch((error) => {
   console.error(error);
+  // Keep what was deployed before the failure available to CI
+  writeReport(error);
   process.exitCode = 1;
 });
-